@@ -1,30 +1,698 @@
 use log::{debug, error, info, warn};
+use regex::Regex;
 use reqwest::Client;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Node, Selector};
 use serde::Deserialize;
 use simplelog::{
     ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::fs::{self, OpenOptions};
 use std::io::BufReader;
 use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
 use url::Url;
 
 const MAX_SEGMENTS: usize = 3;
 const DEFAULT_MAX_DEPTH: usize = 5;
+const DEFAULT_CONCURRENCY: usize = 8;
 
 fn default_max_depth() -> usize {
     DEFAULT_MAX_DEPTH
 }
 
+fn default_user_agent() -> String {
+    "my-web-crawler".to_string()
+}
+
+fn default_concurrency() -> usize {
+    DEFAULT_CONCURRENCY
+}
+
+fn default_burst() -> f64 {
+    1.0
+}
+
+fn default_db_path() -> String {
+    "crawler.db".to_string()
+}
+
+fn default_content_selector() -> String {
+    "main".to_string()
+}
+
+/// How the extracted element is rendered before it is written out.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum OutputFormat {
+    /// Raw `inner_html()` of the selected element (previous behavior).
+    #[default]
+    Html,
+    /// HTML run through an allowlist sanitizer that drops scripts, styles,
+    /// event handlers, and dangerous attributes.
+    SanitizedHtml,
+    /// Readable plain text: sanitized, stripped of markup, whitespace
+    /// collapsed, with block boundaries preserved as newlines.
+    Text,
+}
+
+/// Seconds since the Unix epoch, used to stamp and age stored pages.
+fn now_unix() -> i64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
 #[derive(Deserialize)]
 struct AppConfig {
     start_urls: Vec<String>,
     #[serde(default = "default_max_depth")]
     max_depth: usize,
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    // Rate limiting is opt-in: a non-positive `requests_per_second` disables it.
+    #[serde(default)]
+    requests_per_second: f64,
+    #[serde(default = "default_burst")]
+    burst: f64,
+    #[serde(default = "default_db_path")]
+    db_path: String,
+    // URLs fetched within this many seconds are not re-fetched (links in their
+    // stored body are still followed); 0 disables.
+    #[serde(default)]
+    recrawl_after: u64,
+    #[serde(default = "default_content_selector")]
+    content_selector: String,
+    #[serde(default)]
+    output_format: OutputFormat,
+    #[serde(default)]
+    scope: ScopeConfig,
+}
+
+/// Raw scope rules as read from `config.json`. Compiled into a [`Scope`].
+#[derive(Deserialize, Default)]
+struct ScopeConfig {
+    #[serde(default)]
+    allow_domains: Vec<String>,
+    #[serde(default)]
+    deny_domains: Vec<String>,
+    #[serde(default)]
+    allow_path_patterns: Vec<String>,
+    #[serde(default)]
+    deny_path_patterns: Vec<String>,
+    #[serde(default)]
+    follow_external: bool,
+}
+
+/// Parsed `robots.txt` rules for a single host.
+///
+/// `directives` keeps every `Allow`/`Disallow` path that applies to our
+/// `User-agent` group, each tagged with whether it grants access. The
+/// longest matching prefix decides, and on a tie an `Allow` beats a
+/// `Disallow`.
+#[derive(Default, Clone)]
+struct RobotRules {
+    directives: Vec<(String, bool)>,
+    crawl_delay: Option<Duration>,
+}
+
+impl RobotRules {
+    fn is_allowed(&self, path: &str) -> bool {
+        let mut best: Option<(usize, bool)> = None;
+        for (prefix, allow) in &self.directives {
+            if path.starts_with(prefix.as_str()) {
+                let len = prefix.len();
+                match best {
+                    Some((best_len, best_allow)) => {
+                        // Longer prefix wins; on equal length an Allow overrides a Disallow.
+                        if len > best_len || (len == best_len && *allow && !best_allow) {
+                            best = Some((len, *allow));
+                        }
+                    }
+                    None => best = Some((len, *allow)),
+                }
+            }
+        }
+        best.map(|(_, allow)| allow).unwrap_or(true)
+    }
+}
+
+/// Parse a `robots.txt` body and return the directives of the single group
+/// that applies to `user_agent`. As the standard requires, exactly one group
+/// wins: the most specific non-`*` group whose token is a prefix of our UA
+/// (matched case-insensitively), falling back to the `*` group otherwise.
+/// Directives from other groups — including `*` when a specific group matches
+/// — are ignored rather than unioned.
+fn parse_robots(body: &str, user_agent: &str) -> RobotRules {
+    let ua_lower = user_agent.to_lowercase();
+    // Each group pairs its `User-agent` tokens with the rules that follow them.
+    let mut groups: Vec<(Vec<String>, RobotRules)> = Vec::new();
+    // True while we are still collecting the current group's `User-agent` lines
+    // (i.e. no directive has been seen for it yet).
+    let mut collecting_agents = true;
+
+    for line in body.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let field = field.trim().to_lowercase();
+        let value = value.trim();
+
+        if field == "user-agent" {
+            // A User-agent line following directives starts a fresh group.
+            if !collecting_agents {
+                groups.push((Vec::new(), RobotRules::default()));
+                collecting_agents = true;
+            }
+            if groups.is_empty() {
+                groups.push((Vec::new(), RobotRules::default()));
+            }
+            groups.last_mut().unwrap().0.push(value.to_lowercase());
+            continue;
+        }
+
+        // Directive lines before any User-agent line have no group; skip them.
+        let rules = match groups.last_mut() {
+            Some((_, rules)) => rules,
+            None => continue,
+        };
+        collecting_agents = false;
+
+        match field.as_str() {
+            // An empty Disallow imposes no restriction, so we only record non-empty ones.
+            "disallow" if !value.is_empty() => rules.directives.push((value.to_string(), false)),
+            "allow" if !value.is_empty() => rules.directives.push((value.to_string(), true)),
+            "crawl-delay" => {
+                if let Ok(secs) = value.parse::<f64>() {
+                    rules.crawl_delay = Some(Duration::from_secs_f64(secs));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Pick the most specific matching group: longest non-`*` prefix token wins,
+    // and the `*` group is used only when no specific group matches.
+    let mut best: Option<(usize, &RobotRules)> = None;
+    for (agents, rules) in &groups {
+        for token in agents {
+            let matches = token == "*" || (!token.is_empty() && ua_lower.starts_with(token));
+            if !matches {
+                continue;
+            }
+            let specificity = if token == "*" { 0 } else { token.len() };
+            if best.map_or(true, |(best_spec, _)| specificity > best_spec) {
+                best = Some((specificity, rules));
+            }
+        }
+    }
+
+    best.map(|(_, rules)| rules.clone()).unwrap_or_default()
+}
+
+/// Per-host token-bucket rate limiter.
+///
+/// Each host keeps `(tokens, last_refill)`. Tokens accrue at `rate` per second
+/// up to `capacity`; every request spends one, sleeping until enough have
+/// accrued when the bucket runs dry. A non-positive `rate` disables limiting.
+struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, capacity: f64) -> Self {
+        RateLimiter {
+            rate,
+            // A bucket can never hold less than a single token's worth of burst.
+            capacity: capacity.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, host: &str) {
+        if self.rate <= 0.0 {
+            return;
+        }
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let now = Instant::now();
+                let entry = buckets
+                    .entry(host.to_string())
+                    .or_insert((self.capacity, now));
+                let elapsed = now.duration_since(entry.1).as_secs_f64();
+                entry.0 = (entry.0 + elapsed * self.rate).min(self.capacity);
+                entry.1 = now;
+                if entry.0 >= 1.0 {
+                    entry.0 -= 1.0;
+                    None
+                } else {
+                    Some(Duration::from_secs_f64((1.0 - entry.0) / self.rate))
+                }
+            };
+            match wait {
+                None => break,
+                Some(delay) => tokio::time::sleep(delay).await,
+            }
+        }
+    }
+}
+
+/// A previously stored page, used to drive conditional requests and the
+/// incremental recrawl decision.
+struct PageRecord {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    last_fetched: i64,
+    body: String,
+}
+
+/// SQLite-backed page store. Persists each crawled URL with its status,
+/// fetch time, HTTP validators, and body so subsequent runs can issue
+/// conditional requests and resume instead of starting from scratch.
+struct Store {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl Store {
+    fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pages (
+                url           TEXT PRIMARY KEY,
+                status        INTEGER NOT NULL,
+                last_fetched  INTEGER NOT NULL,
+                etag          TEXT,
+                last_modified TEXT,
+                body          TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Store {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn get(&self, url: &str) -> Option<PageRecord> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT etag, last_modified, last_fetched, body FROM pages WHERE url = ?1",
+            [url],
+            |row| {
+                Ok(PageRecord {
+                    etag: row.get(0)?,
+                    last_modified: row.get(1)?,
+                    last_fetched: row.get(2)?,
+                    body: row.get(3)?,
+                })
+            },
+        )
+        .ok()
+    }
+
+    fn upsert(
+        &self,
+        url: &str,
+        status: u16,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+        body: &str,
+    ) {
+        let conn = self.conn.lock().unwrap();
+        if let Err(e) = conn.execute(
+            "INSERT INTO pages (url, status, last_fetched, etag, last_modified, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(url) DO UPDATE SET
+                status = excluded.status,
+                last_fetched = excluded.last_fetched,
+                etag = excluded.etag,
+                last_modified = excluded.last_modified,
+                body = excluded.body",
+            rusqlite::params![url, status, now_unix(), etag, last_modified, body],
+        ) {
+            error!("Failed to store {}: {}", url, e);
+        }
+    }
+
+    /// Refresh only the fetch time after a `304 Not Modified`, leaving the
+    /// cached body and validators untouched.
+    fn touch(&self, url: &str) {
+        let conn = self.conn.lock().unwrap();
+        let _ = conn.execute(
+            "UPDATE pages SET last_fetched = ?2 WHERE url = ?1",
+            rusqlite::params![url, now_unix()],
+        );
+    }
+}
+
+/// Read a single response header as an owned string, if present and valid.
+fn header_value(headers: &reqwest::header::HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// Decides which discovered links are in scope. Rules are applied deny-first:
+/// a matching deny rule always rejects, then external hosts are gated by
+/// `follow_external`/`allow_domains`, and finally any path allowlist must match.
+struct Scope {
+    /// Domains of the configured start URLs, always treated as internal.
+    base_domains: HashSet<String>,
+    allow_domains: Vec<String>,
+    deny_domains: Vec<String>,
+    allow_path: Vec<Regex>,
+    deny_path: Vec<Regex>,
+    follow_external: bool,
+}
+
+impl Scope {
+    fn new(config: ScopeConfig, base_domains: HashSet<String>) -> Self {
+        // Invalid patterns are dropped with a warning rather than aborting the crawl.
+        let compile = |patterns: Vec<String>| -> Vec<Regex> {
+            patterns
+                .into_iter()
+                .filter_map(|p| match Regex::new(&p) {
+                    Ok(re) => Some(re),
+                    Err(e) => {
+                        warn!("Ignoring invalid path pattern {:?}: {}", p, e);
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        Scope {
+            base_domains,
+            allow_domains: config.allow_domains,
+            deny_domains: config.deny_domains,
+            allow_path: compile(config.allow_path_patterns),
+            deny_path: compile(config.deny_path_patterns),
+            follow_external: config.follow_external,
+        }
+    }
+
+    fn should_visit(&self, url: &Url) -> bool {
+        let domain = url.domain().unwrap_or("");
+        let path = url.path();
+
+        // Deny rules win over everything else.
+        if self.deny_domains.iter().any(|d| d == domain) {
+            return false;
+        }
+        if self.deny_path.iter().any(|re| re.is_match(path)) {
+            return false;
+        }
+
+        // External hosts require follow_external or an explicit allow entry.
+        let internal = self.base_domains.contains(domain);
+        let allowed_domain = self.allow_domains.iter().any(|d| d == domain);
+        if !internal && !allowed_domain && !self.follow_external {
+            return false;
+        }
+
+        // When a path allowlist is configured, the path must match one of them.
+        if !self.allow_path.is_empty() && !self.allow_path.iter().any(|re| re.is_match(path)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// State shared by every worker task: the BFS work queue plus the caches and
+/// client the crawl needs. Wrapped in an `Arc` and handed to each worker.
+struct Crawler {
+    client: Client,
+    user_agent: String,
+    max_depth: usize,
+    queue: Mutex<VecDeque<(Url, usize)>>,
+    // Number of items enqueued but not yet fully processed; the crawl is done
+    // once this reaches zero.
+    pending: AtomicUsize,
+    idle: Notify,
+    visited: Mutex<HashSet<String>>,
+    robots: Mutex<HashMap<String, RobotRules>>,
+    last_fetch: Mutex<HashMap<String, Instant>>,
+    rate_limiter: RateLimiter,
+    store: Store,
+    recrawl_after: Option<u64>,
+    content_selector: String,
+    output_format: OutputFormat,
+    scope: Scope,
+}
+
+impl Crawler {
+    /// Push a URL onto the work queue and wake a worker to pick it up.
+    fn enqueue(&self, url: Url, depth: usize) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().unwrap().push_back((url, depth));
+        self.idle.notify_one();
+    }
+
+    /// Worker loop: pop URLs until the queue drains and no work is in flight.
+    async fn run_worker(self: Arc<Self>) {
+        loop {
+            let item = self.queue.lock().unwrap().pop_front();
+            match item {
+                Some((url, depth)) => {
+                    self.crawl(url, depth).await;
+                    // This item is finished; if it was the last one, release the
+                    // other idle workers so they can exit too.
+                    if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        self.idle.notify_waiters();
+                    }
+                }
+                None => {
+                    if self.pending.load(Ordering::SeqCst) == 0 {
+                        self.idle.notify_waiters();
+                        break;
+                    }
+                    // Register as a waiter *before* the final check. `enable()`
+                    // commits the registration immediately, so a notify_waiters()
+                    // fired after the check still wakes us instead of being lost.
+                    let notified = self.idle.notified();
+                    tokio::pin!(notified);
+                    notified.as_mut().enable();
+                    if self.pending.load(Ordering::SeqCst) == 0 {
+                        self.idle.notify_waiters();
+                        break;
+                    }
+                    notified.await;
+                }
+            }
+        }
+    }
+
+    /// Fetch and cache the `robots.txt` rules for `host`, downloading it on
+    /// first use. Hosts that fail to serve a usable `robots.txt` are treated as
+    /// fully allowed and cached as such so we don't re-request it per page.
+    async fn ensure_robots(&self, host: &str) -> RobotRules {
+        if let Some(rules) = self.robots.lock().unwrap().get(host) {
+            return rules.clone();
+        }
+
+        let robots_url = format!("https://{}/robots.txt", host);
+        let rules = match self.client.get(&robots_url).send().await {
+            Ok(response) if response.status().is_success() => match response.text().await {
+                Ok(body) => parse_robots(&body, &self.user_agent),
+                Err(_) => RobotRules::default(),
+            },
+            _ => RobotRules::default(),
+        };
+
+        self.robots
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), rules.clone());
+        rules
+    }
+
+    async fn crawl(&self, url: Url, depth: usize) {
+        if depth > self.max_depth {
+            return;
+        }
+
+        {
+            let mut visited = self.visited.lock().unwrap();
+            if visited.contains(url.as_str()) {
+                return;
+            }
+            visited.insert(url.to_string());
+        }
+
+        // Pull any previously stored copy to drive the incremental recrawl.
+        let record = self.store.get(url.as_str());
+        if let (Some(rec), Some(window)) = (&record, self.recrawl_after) {
+            let age = now_unix().saturating_sub(rec.last_fetched);
+            if age < window as i64 {
+                info!("Skipping fetch (fetched {}s ago): {}", age, url);
+                // Don't re-fetch, but still follow the cached body's links so an
+                // incremental recrawl keeps discovering the rest of the site.
+                self.parse_links(&url, &rec.body, depth);
+                return;
+            }
+        }
+
+        // Consult robots.txt before touching the host.
+        let host = url.host_str().unwrap_or("").to_string();
+        let rules = self.ensure_robots(&host).await;
+        if !rules.is_allowed(url.path()) {
+            info!("Skipping (disallowed by robots.txt): {}", url);
+            return;
+        }
+
+        // Honor Crawl-delay by spacing out requests to the same host.
+        if let Some(delay) = rules.crawl_delay {
+            loop {
+                let mut last = self.last_fetch.lock().unwrap();
+                if let Some(prev) = last.get(&host) {
+                    let elapsed = prev.elapsed();
+                    if elapsed < delay {
+                        let remaining = delay - elapsed;
+                        drop(last);
+                        tokio::time::sleep(remaining).await;
+                        continue;
+                    }
+                }
+                last.insert(host.clone(), Instant::now());
+                break;
+            }
+        }
+
+        //println!("Crawling: {}", url);
+        info!("Crawling: {}", url);
+
+        // Throttle requests to this origin before hitting the network.
+        self.rate_limiter.acquire(&host).await;
+
+        // Issue a conditional request when we already hold validators.
+        let mut request = self.client.get(url.as_str());
+        if let Some(rec) = &record {
+            if let Some(etag) = &rec.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &rec.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status == reqwest::StatusCode::NOT_MODIFIED {
+                    // Unchanged: keep the cached body but still follow its links.
+                    info!("Not modified: {}", url);
+                    self.store.touch(url.as_str());
+                    if let Some(rec) = &record {
+                        self.parse_links(&url, &rec.body, depth);
+                    }
+                } else if status.is_success() {
+                    let etag = header_value(response.headers(), reqwest::header::ETAG);
+                    let last_modified =
+                        header_value(response.headers(), reqwest::header::LAST_MODIFIED);
+                    if let Ok(body) = response.text().await {
+                        self.save_content(&url, &body);
+                        self.store.upsert(
+                            url.as_str(),
+                            status.as_u16(),
+                            etag.as_deref(),
+                            last_modified.as_deref(),
+                            &body,
+                        );
+                        self.parse_links(&url, &body, depth);
+                    }
+                } else {
+                    error!("Failed to fetch {}: Status {}", url, status);
+                }
+            }
+            Err(e) => {
+                error!("Failed to fetch {}: {}", url, e);
+            }
+        }
+    }
+
+    /// Extract the configured content element, render it per `output_format`,
+    /// and persist the result to the flat-file sink.
+    fn save_content(&self, url: &Url, html: &str) {
+        let document = Html::parse_document(html);
+        let selector = match Selector::parse(&self.content_selector) {
+            Ok(selector) => selector,
+            Err(_) => {
+                warn!("Invalid content_selector: {}", self.content_selector);
+                return;
+            }
+        };
+
+        if let Some(element) = document.select(&selector).next() {
+            let raw_html = element.inner_html();
+            let content = match self.output_format {
+                OutputFormat::Html => raw_html,
+                OutputFormat::SanitizedHtml => ammonia::clean(&raw_html),
+                OutputFormat::Text => {
+                    // Sanitize first so scripts/styles never reach the text.
+                    let cleaned = ammonia::clean(&raw_html);
+                    let fragment = Html::parse_fragment(&cleaned);
+                    to_plain_text(fragment.root_element())
+                }
+            };
+            write_to_file(url, &content);
+        }
+    }
+
+    /// Extract the links from `html` that pass the scope rules and enqueue them
+    /// for the workers.
+    fn parse_links(&self, base_url: &Url, html: &str, depth: usize) {
+        let document = Html::parse_document(html);
+        let selector = Selector::parse("a[href]").unwrap();
+
+        let mut links_to_visit = Vec::new();
+
+        for element in document.select(&selector) {
+            if let Some(href) = element.value().attr("href") {
+                debug!("Found link: {}, depth: {}", href, depth);
+
+                if let Ok(mut absolute_url) = base_url.join(href) {
+                    debug!("Absolute URL: {}, depth: {}", absolute_url, depth);
+                    // Remove fragment (anchor) to ensure we crawl the page, not just a section
+                    absolute_url.set_fragment(None);
+
+                    // Apply the configured scope rules (domain + path).
+                    if self.scope.should_visit(&absolute_url) {
+                        links_to_visit.push(absolute_url);
+                    } else {
+                        debug!("Skip as the link is out of scope. {}", absolute_url);
+                    }
+                } else {
+                    warn!("Failed to join URL with base URL. Found link is {}", href);
+                }
+            }
+        }
+
+        // Deduplicate links to avoid processing the same URL multiple times from this page
+        links_to_visit.sort();
+        links_to_visit.dedup();
+
+        for link in links_to_visit {
+            self.enqueue(link, depth + 1);
+        }
+    }
 }
 
 #[tokio::main]
@@ -59,141 +727,132 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config: AppConfig = serde_json::from_reader(reader)?;
 
     let mut start_urls = config.start_urls;
-    let max_depth = config.max_depth;
+    let user_agent = config.user_agent;
+    let concurrency = config.concurrency.max(1);
     // Deduplicate and sort start URLs to avoid processing the same URL multiple times
     // start_urls.sort();
     start_urls.dedup();
 
-    let visited = Arc::new(Mutex::new(HashSet::new()));
-    let client = Client::new();
+    // The start URLs' domains seed the scope as the implicitly-internal set.
+    let base_domains: HashSet<String> = start_urls
+        .iter()
+        .filter_map(|u| Url::parse(u).ok())
+        .filter_map(|u| u.domain().map(str::to_string))
+        .collect();
+
+    let client = Client::builder().user_agent(user_agent.clone()).build()?;
+    let store = Store::open(&config.db_path)?;
+    let recrawl_after = (config.recrawl_after > 0).then_some(config.recrawl_after);
+    let scope = Scope::new(config.scope, base_domains);
+    let crawler = Arc::new(Crawler {
+        client,
+        user_agent,
+        max_depth: config.max_depth,
+        queue: Mutex::new(VecDeque::new()),
+        pending: AtomicUsize::new(0),
+        idle: Notify::new(),
+        visited: Mutex::new(HashSet::new()),
+        robots: Mutex::new(HashMap::new()),
+        last_fetch: Mutex::new(HashMap::new()),
+        rate_limiter: RateLimiter::new(config.requests_per_second, config.burst),
+        store,
+        recrawl_after,
+        content_selector: config.content_selector,
+        output_format: config.output_format,
+        scope,
+    });
 
+    // Seed the queue before launching workers so none of them exit early.
     for url in start_urls {
-        crawl(client.clone(), Url::parse(&url)?, visited.clone(), 0, max_depth).await;
+        crawler.enqueue(Url::parse(&url)?, 0);
+    }
+
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency {
+        workers.spawn(crawler.clone().run_worker());
     }
+    while workers.join_next().await.is_some() {}
 
     Ok(())
 }
 
-async fn crawl(
-    client: Client,
-    url: Url,
-    visited: Arc<Mutex<HashSet<String>>>,
-    depth: usize,
-    max_depth: usize,
-) {
-    if depth > max_depth {
-        return;
-    }
-
-    let mut visited_lock = visited.lock().unwrap();
-    if visited_lock.contains(url.as_str()) {
-        return;
+/// Reduce a sanitized element to readable plain text: text nodes are kept,
+/// block-level elements introduce line breaks, and runs of whitespace are
+/// collapsed so the output stays legible.
+fn to_plain_text(element: ElementRef) -> String {
+    fn is_block(name: &str) -> bool {
+        matches!(
+            name,
+            "p" | "div"
+                | "br"
+                | "li"
+                | "ul"
+                | "ol"
+                | "section"
+                | "article"
+                | "header"
+                | "footer"
+                | "table"
+                | "tr"
+                | "blockquote"
+                | "pre"
+                | "h1"
+                | "h2"
+                | "h3"
+                | "h4"
+                | "h5"
+                | "h6"
+        )
     }
-    visited_lock.insert(url.to_string());
-    drop(visited_lock);
-
-    //println!("Crawling: {}", url);
-    info!("Crawling: {}", url);
 
-    match client.get(url.as_str()).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                if let Ok(body) = response.text().await {
-                    save_to_file(&url, &body);
-                    parse_links(&client, &url, &body, visited.clone(), depth, max_depth).await;
+    let mut raw = String::new();
+    for node in element.descendants() {
+        match node.value() {
+            Node::Element(e) => {
+                if is_block(e.name()) && !raw.ends_with('\n') {
+                    raw.push('\n');
                 }
-            } else {
-                error!("Failed to fetch {}: Status {}", url, response.status());
             }
-        }
-        Err(e) => {
-            error!("Failed to fetch {}: {}", url, e);
+            Node::Text(text) => raw.push_str(text),
+            _ => {}
         }
     }
-}
-
-fn save_to_file(url: &Url, html: &str) {
-    let document = Html::parse_document(html);
-    let selector = Selector::parse("main").unwrap();
-
-    if let Some(body) = document.select(&selector).next() {
-        // URL全体を文字列化し、プロトコル削除後、（英数字とピリオド）以外を "_" に置換
-        let raw_filename = url
-            .to_string()
-            .replace("https://", "")
-            .replace("http://", "")
-            .replace(|c: char| !c.is_alphanumeric() && !c.eq(&'.'), "_");
-
-        // "_" で分割してセグメントを取得
-        let segments: Vec<&str> = raw_filename.split('_').filter(|s| !s.is_empty()).collect();
-
-        // Rule1 & Rule2 に基づくファイル名の決定
-        let filename = if segments.len() <= MAX_SEGMENTS {
-            // Rule1: セグメント数がMAX_SEGMENTS以内の場合はファイルをマージしない (全セグメントを使用)
-            segments.join("_")
-        } else {
-            // Rule2: セグメント数がMAX_SEGMENTSを超える場合は、MAX_SEGMENTS番目セグメントまでのパスでマージする
-            segments[..MAX_SEGMENTS].join("_")
-        };
 
-        let file_path = format!("crawled_pages/{}.txt", filename);
-
-        // 追記モードでファイルを開く
-        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(file_path) {
-            // 区切り線とURLをヘッダーとして書き込む
-            let header = format!("\n\n========================================\nURL: {}\n========================================\n", url);
-            let _ = file.write_all(header.as_bytes());
-            let _ = file.write_all(body.inner_html().as_bytes());
-        }
-    }
+    raw.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
-async fn parse_links(
-    client: &Client,
-    base_url: &Url,
-    html: &str,
-    visited: Arc<Mutex<HashSet<String>>>,
-    depth: usize,
-    max_depth: usize,
-) {
-    let document = Html::parse_document(html);
-    let selector = Selector::parse("a[href]").unwrap();
-
-    let mut links_to_visit = Vec::new();
-
-    for element in document.select(&selector) {
-        if let Some(href) = element.value().attr("href") {
-            debug!("Found link: {}, depth: {}", href, depth);
+/// Append `content` to the flat-file sink, deriving the filename from `url`.
+fn write_to_file(url: &Url, content: &str) {
+    // URL全体を文字列化し、プロトコル削除後、（英数字とピリオド）以外を "_" に置換
+    let raw_filename = url
+        .to_string()
+        .replace("https://", "")
+        .replace("http://", "")
+        .replace(|c: char| !c.is_alphanumeric() && !c.eq(&'.'), "_");
 
-            if let Ok(mut absolute_url) = base_url.join(href) {
-                debug!("Absolute URL: {}, depth: {}", absolute_url, depth);
-                // Remove fragment (anchor) to ensure we crawl the page, not just a section
-                absolute_url.set_fragment(None);
+    // "_" で分割してセグメントを取得
+    let segments: Vec<&str> = raw_filename.split('_').filter(|s| !s.is_empty()).collect();
 
-                // Check if the link is within the same domain
-                if absolute_url.domain() == base_url.domain() {
-                    links_to_visit.push(absolute_url);
-                } else {
-                    debug!(
-                        "Skip as the Link is external to this domain. {}",
-                        absolute_url
-                    );
-                }
-            } else {
-                warn!("Failed to join URL with base URL. Found link is {}", href);
-            }
-        }
-    }
+    // Rule1 & Rule2 に基づくファイル名の決定
+    let filename = if segments.len() <= MAX_SEGMENTS {
+        // Rule1: セグメント数がMAX_SEGMENTS以内の場合はファイルをマージしない (全セグメントを使用)
+        segments.join("_")
+    } else {
+        // Rule2: セグメント数がMAX_SEGMENTSを超える場合は、MAX_SEGMENTS番目セグメントまでのパスでマージする
+        segments[..MAX_SEGMENTS].join("_")
+    };
 
-    // Deduplicate links to avoid processing the same URL multiple times from this page
-    links_to_visit.sort();
-    links_to_visit.dedup();
+    let file_path = format!("crawled_pages/{}.txt", filename);
 
-    for link in links_to_visit {
-        let visited_clone = visited.clone();
-        let client_clone = client.clone();
-        // Recursive call with depth increment
-        // Box::pin is required to handle recursion in async functions
-        Box::pin(crawl(client_clone, link, visited_clone, depth + 1, max_depth)).await;
+    // 追記モードでファイルを開く
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(file_path) {
+        // 区切り線とURLをヘッダーとして書き込む
+        let header = format!("\n\n========================================\nURL: {}\n========================================\n", url);
+        let _ = file.write_all(header.as_bytes());
+        let _ = file.write_all(content.as_bytes());
     }
 }